@@ -0,0 +1,234 @@
+//! CIDR aggregation: collapse a combined country+ASN block list down to the
+//! smallest equivalent set of prefixes so HAProxy has fewer entries to test.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// Outcome of an aggregation pass: the minimized CIDR list plus how many
+/// input blocks were eliminated by subsumption or sibling coalescing.
+pub struct AggregationResult {
+    pub cidrs: Vec<String>,
+    pub eliminated: usize,
+}
+
+enum Parsed {
+    V4(u32, u8),
+    V6(u128, u8),
+}
+
+fn parse_cidr(block: &str) -> Option<Parsed> {
+    let (addr_part, len_part) = block.split_once('/')?;
+    let len: u8 = len_part.trim().parse().ok()?;
+
+    if let Ok(addr) = addr_part.trim().parse::<Ipv4Addr>() {
+        if len > 32 {
+            return None;
+        }
+        return Some(Parsed::V4(u32::from(addr), len));
+    }
+    if let Ok(addr) = addr_part.trim().parse::<Ipv6Addr>() {
+        if len > 128 {
+            return None;
+        }
+        return Some(Parsed::V6(u128::from(addr), len));
+    }
+    None
+}
+
+fn mask_v4(len: u8) -> u32 {
+    if len == 0 {
+        0
+    } else {
+        (!0u32) << (32 - len as u32)
+    }
+}
+
+fn mask_v6(len: u8) -> u128 {
+    if len == 0 {
+        0
+    } else {
+        (!0u128) << (128 - len as u32)
+    }
+}
+
+fn reduce_v4(blocks: Vec<(u32, u8)>) -> (Vec<(u32, u8)>, usize) {
+    let mut eliminated = 0;
+
+    let mut normalized: Vec<(u32, u8)> =
+        blocks.into_iter().map(|(a, l)| (a & mask_v4(l), l)).collect();
+    normalized.sort();
+    normalized.dedup();
+
+    // Subsumption: drop any block fully contained in the previous kept block.
+    let mut kept: Vec<(u32, u8)> = Vec::with_capacity(normalized.len());
+    for (addr, len) in normalized {
+        if let Some(&(prev_addr, prev_len)) = kept.last() {
+            let prev_end = prev_addr | !mask_v4(prev_len);
+            let end = addr | !mask_v4(len);
+            if addr >= prev_addr && end <= prev_end {
+                eliminated += 1;
+                continue;
+            }
+        }
+        kept.push((addr, len));
+    }
+
+    // Coalesce sibling pairs into their shared parent until no more merge.
+    loop {
+        let mut merged = Vec::with_capacity(kept.len());
+        let mut changed = false;
+        let mut i = 0;
+        while i < kept.len() {
+            if i + 1 < kept.len() {
+                let (a_addr, a_len) = kept[i];
+                let (b_addr, b_len) = kept[i + 1];
+                if a_len == b_len && a_len > 1 {
+                    let bit = 1u32 << (32 - a_len as u32);
+                    if a_addr & bit == 0 && b_addr == a_addr | bit {
+                        merged.push((a_addr, a_len - 1));
+                        eliminated += 1;
+                        changed = true;
+                        i += 2;
+                        continue;
+                    }
+                }
+            }
+            merged.push(kept[i]);
+            i += 1;
+        }
+        kept = merged;
+        if !changed {
+            break;
+        }
+        kept.sort();
+    }
+
+    (kept, eliminated)
+}
+
+fn reduce_v6(blocks: Vec<(u128, u8)>) -> (Vec<(u128, u8)>, usize) {
+    let mut eliminated = 0;
+
+    let mut normalized: Vec<(u128, u8)> =
+        blocks.into_iter().map(|(a, l)| (a & mask_v6(l), l)).collect();
+    normalized.sort();
+    normalized.dedup();
+
+    let mut kept: Vec<(u128, u8)> = Vec::with_capacity(normalized.len());
+    for (addr, len) in normalized {
+        if let Some(&(prev_addr, prev_len)) = kept.last() {
+            let prev_end = prev_addr | !mask_v6(prev_len);
+            let end = addr | !mask_v6(len);
+            if addr >= prev_addr && end <= prev_end {
+                eliminated += 1;
+                continue;
+            }
+        }
+        kept.push((addr, len));
+    }
+
+    loop {
+        let mut merged = Vec::with_capacity(kept.len());
+        let mut changed = false;
+        let mut i = 0;
+        while i < kept.len() {
+            if i + 1 < kept.len() {
+                let (a_addr, a_len) = kept[i];
+                let (b_addr, b_len) = kept[i + 1];
+                if a_len == b_len && a_len > 1 {
+                    let bit = 1u128 << (128 - a_len as u32);
+                    if a_addr & bit == 0 && b_addr == a_addr | bit {
+                        merged.push((a_addr, a_len - 1));
+                        eliminated += 1;
+                        changed = true;
+                        i += 2;
+                        continue;
+                    }
+                }
+            }
+            merged.push(kept[i]);
+            i += 1;
+        }
+        kept = merged;
+        if !changed {
+            break;
+        }
+        kept.sort();
+    }
+
+    (kept, eliminated)
+}
+
+/// Aggregate a combined list of CIDR blocks, never merging across address
+/// families or across alignment boundaries. Unparsable lines are dropped
+/// with a warning and counted as eliminated.
+pub fn aggregate(blocks: &[String]) -> AggregationResult {
+    let mut v4 = Vec::new();
+    let mut v6 = Vec::new();
+    let mut eliminated = 0;
+
+    for block in blocks {
+        match parse_cidr(block) {
+            Some(Parsed::V4(addr, len)) => v4.push((addr, len)),
+            Some(Parsed::V6(addr, len)) => v6.push((addr, len)),
+            None => {
+                eprintln!("Warning: skipping unparsable CIDR block: {}", block);
+                eliminated += 1;
+            }
+        }
+    }
+
+    let (v4, v4_eliminated) = reduce_v4(v4);
+    let (v6, v6_eliminated) = reduce_v6(v6);
+    eliminated += v4_eliminated + v6_eliminated;
+
+    let mut cidrs: Vec<String> = v4
+        .into_iter()
+        .map(|(addr, len)| format!("{}/{}", Ipv4Addr::from(addr), len))
+        .collect();
+    cidrs.extend(
+        v6.into_iter()
+            .map(|(addr, len)| format!("{}/{}", Ipv6Addr::from(addr), len)),
+    );
+
+    AggregationResult { cidrs, eliminated }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blocks(strs: &[&str]) -> Vec<String> {
+        strs.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn subsumes_contained_blocks() {
+        let result = aggregate(&blocks(&["10.0.0.0/24", "10.0.0.0/23", "10.0.1.0/24"]));
+        assert_eq!(result.cidrs, vec!["10.0.0.0/23".to_string()]);
+        assert_eq!(result.eliminated, 2);
+    }
+
+    #[test]
+    fn coalesces_sibling_pair() {
+        let result = aggregate(&blocks(&["10.0.0.0/24", "10.0.1.0/24"]));
+        assert_eq!(result.cidrs, vec!["10.0.0.0/23".to_string()]);
+        assert_eq!(result.eliminated, 1);
+    }
+
+    #[test]
+    fn never_coalesces_down_to_default_route() {
+        let v4 = aggregate(&blocks(&["0.0.0.0/1", "128.0.0.0/1"]));
+        assert_eq!(v4.cidrs, vec!["0.0.0.0/1".to_string(), "128.0.0.0/1".to_string()]);
+
+        let v6 = aggregate(&blocks(&["::/1", "8000::/1"]));
+        assert_eq!(v6.cidrs, vec!["::/1".to_string(), "8000::/1".to_string()]);
+    }
+
+    #[test]
+    fn keeps_v4_and_v6_separate() {
+        let result = aggregate(&blocks(&["192.168.0.0/24", "2001:db8::/32"]));
+        assert_eq!(result.cidrs.len(), 2);
+        assert!(result.cidrs.contains(&"192.168.0.0/24".to_string()));
+        assert!(result.cidrs.contains(&"2001:db8::/32".to_string()));
+    }
+}
@@ -0,0 +1,79 @@
+//! A shared fetch helper applied to every upstream request. Connection
+//! resets and 5xx responses are retried with exponential backoff and
+//! jitter; 4xx responses are treated as fatal since retrying won't help.
+
+use rand::Rng;
+use reqwest::{RequestBuilder, Response, StatusCode};
+use std::fmt;
+use std::time::Duration;
+use tokio::time::sleep;
+
+const MAX_ATTEMPTS: u32 = 3;
+const BASE_DELAY: Duration = Duration::from_millis(200);
+
+#[derive(Debug)]
+pub(crate) enum FetchError {
+    Status(StatusCode),
+    Transport(reqwest::Error),
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FetchError::Status(status) => write!(f, "HTTP {}", status),
+            FetchError::Transport(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for FetchError {}
+
+fn is_retryable_transport_error(err: &reqwest::Error) -> bool {
+    err.is_connect() || err.is_timeout() || err.is_request()
+}
+
+/// Send a request built fresh by `build` on each attempt, retrying
+/// connection resets and 5xx responses up to `MAX_ATTEMPTS` times with
+/// exponential backoff and jitter. 4xx responses and non-retryable
+/// transport errors return immediately.
+pub(crate) async fn send_with_retry<F>(build: F) -> Result<Response, FetchError>
+where
+    F: Fn() -> RequestBuilder,
+{
+    let mut delay = BASE_DELAY;
+    let mut last_err = None;
+
+    for attempt in 1..=MAX_ATTEMPTS {
+        match build().send().await {
+            Ok(response) => {
+                let status = response.status();
+                if !status.is_server_error() {
+                    return Ok(response);
+                }
+                eprintln!(
+                    "Warning: request failed with {} (attempt {}/{})",
+                    status, attempt, MAX_ATTEMPTS
+                );
+                last_err = Some(FetchError::Status(status));
+            }
+            Err(e) => {
+                if !is_retryable_transport_error(&e) {
+                    return Err(FetchError::Transport(e));
+                }
+                eprintln!(
+                    "Warning: request error: {} (attempt {}/{})",
+                    e, attempt, MAX_ATTEMPTS
+                );
+                last_err = Some(FetchError::Transport(e));
+            }
+        }
+
+        if attempt < MAX_ATTEMPTS {
+            let jitter = Duration::from_millis(rand::thread_rng().gen_range(0..100));
+            sleep(delay + jitter).await;
+            delay *= 2;
+        }
+    }
+
+    Err(last_err.expect("loop always runs at least once"))
+}
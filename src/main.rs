@@ -1,20 +1,41 @@
-use clap::Parser;
+mod aggregate;
+mod fetch;
+mod output;
+mod serve;
+mod tls;
+
+use clap::{Parser, Subcommand};
+use futures_util::StreamExt;
 use reqwest::header::{HeaderMap, HeaderValue};
 use reqwest::StatusCode;
 use sha2::{Digest, Sha256};
+use std::fmt;
 use std::fs;
-use std::io::{self, BufRead, BufReader};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
 
 const FILE_URL: &str = "https://wetmore.ca/ip/haproxy_geo_ip.txt";
 const SHA256_URL: &str = "https://wetmore.ca/ip/haproxy_geo_ip.sha256";
 const LOCAL_FILE_PATH: &str = "haproxy_geo_ip.txt";
-const LOCAL_FILE_CIDR: &str = "okcidr.txt";
 const ASN_BASE_URL: &str = "https://raw.githubusercontent.com/ipverse/asn-ip/master/as";
 
 #[derive(Parser, Debug)]
 #[command(name = "ha-geo-ip")]
 #[command(about = "Filter IP geolocation data by country codes", long_about = None)]
-struct Args {
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Fetch the upstream data once, filter it, and write the block list (default workflow)
+    Generate(GenerateArgs),
+    /// Periodically refresh the block list and serve it over HTTP for other proxies to pull
+    Serve(serve::ServeArgs),
+}
+
+#[derive(Parser, Debug, Clone)]
+pub(crate) struct GenerateArgs {
     /// Country codes to filter (can be specified multiple times)
     #[arg(short = 'c', long = "country", required = true)]
     country_codes: Vec<String>,
@@ -22,12 +43,122 @@ struct Args {
     /// ASN numbers to include (can be specified multiple times)
     #[arg(short = 'a', long = "asn")]
     asn_numbers: Vec<String>,
+
+    /// Aggregate the combined CIDR list, dropping subsumed blocks and
+    /// coalescing sibling prefixes before writing it out
+    #[arg(long = "aggregate")]
+    aggregate: bool,
+
+    /// Output format for the filtered block list
+    #[arg(long = "format", value_enum, default_value = "cidr")]
+    format: output::Format,
+
+    /// Override the output filename (defaults depend on --format)
+    #[arg(long = "output")]
+    output: Option<String>,
+
+    /// Set/map name used by the nftables and ipset formats
+    #[arg(long = "set-name", default_value = "ok_set")]
+    set_name: String,
+
+    /// Only fetch IPv4 ASN ranges (default)
+    #[arg(long = "ipv4-only", conflicts_with_all = ["ipv6", "dual"])]
+    ipv4_only: bool,
+
+    /// Only fetch IPv6 ASN ranges
+    #[arg(long = "ipv6", conflicts_with_all = ["ipv4_only", "dual"])]
+    ipv6: bool,
+
+    /// Fetch both IPv4 and IPv6 ASN ranges
+    #[arg(long = "dual", conflicts_with_all = ["ipv4_only", "ipv6"])]
+    dual: bool,
+
+    /// Reject the TLS connection unless the server's certificate SPKI
+    /// hashes (SHA256, hex) to this value
+    #[arg(long = "pin-sha256")]
+    pub(crate) pin_sha256: Option<String>,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Args::parse();
+    let cli = Cli::parse();
+
+    match cli.command {
+        Command::Generate(args) => {
+            let client = tls::build_client(args.pin_sha256.as_deref())?;
+            let output = run_generate(&client, &args).await?;
+            println!("\nBlocks written to: {}", output.output_path);
+            Ok(())
+        }
+        Command::Serve(args) => serve::run(args).await,
+    }
+}
 
+/// Result of one fetch-filter-render pass, shared by the one-shot `generate`
+/// command and each periodic refresh in `serve` mode.
+pub(crate) struct GenerateOutput {
+    pub(crate) rendered: Vec<u8>,
+    pub(crate) output_path: String,
+    pub(crate) total_blocks: usize,
+}
+
+/// Failures while fetching/validating the upstream geo-IP file. Returned
+/// rather than exiting the process, since `run_generate` is also called on
+/// every periodic tick by `serve::refresh`, which must be able to log a
+/// failed refresh and keep serving the last good block list.
+#[derive(Debug)]
+enum FetchFileError {
+    UnexpectedStatus(StatusCode),
+    HashMismatch { expected: String, calculated: String },
+}
+
+impl fmt::Display for FetchFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FetchFileError::UnexpectedStatus(status) => {
+                write!(f, "failed to fetch file: unexpected HTTP status {}", status)
+            }
+            FetchFileError::HashMismatch {
+                expected,
+                calculated,
+            } => write!(
+                f,
+                "SHA256 mismatch: expected {}, calculated {}",
+                expected, calculated
+            ),
+        }
+    }
+}
+
+impl std::error::Error for FetchFileError {}
+
+/// Stream `response`'s body to `path` while hashing it in-flight, so we never
+/// hold the whole geo-IP list in memory. Returns the hex-encoded SHA256 of
+/// the written bytes; the caller is responsible for removing `path` if this
+/// returns an error partway through the transfer.
+async fn stream_to_file(
+    response: reqwest::Response,
+    path: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let mut hasher = Sha256::new();
+    let temp_file = fs::File::create(path)?;
+    let mut writer = BufWriter::new(temp_file);
+    let mut stream = response.bytes_stream();
+
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        hasher.update(&chunk);
+        writer.write_all(&chunk)?;
+    }
+    writer.flush()?;
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+pub(crate) async fn run_generate(
+    client: &reqwest::Client,
+    args: &GenerateArgs,
+) -> Result<GenerateOutput, Box<dyn std::error::Error>> {
     // Convert all country codes to uppercase for case-insensitive matching
     let country_codes: Vec<String> = args
         .country_codes
@@ -35,7 +166,6 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .map(|cc| cc.to_uppercase())
         .collect();
 
-    let client = reqwest::Client::new();
     let mut headers = HeaderMap::new();
 
     // Check for local file and get its modification time for an If-Modified-Since header
@@ -49,58 +179,109 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
 
     println!("Fetching IP geolocation data from: {}", FILE_URL);
-    let response = client.get(FILE_URL).headers(headers).send().await?;
+    let response = fetch::send_with_retry(|| client.get(FILE_URL).headers(headers.clone())).await?;
 
     let content = match response.status() {
         StatusCode::OK => {
             println!("New version of the file found, downloading...");
-            let content = response.bytes().await?;
 
-            // Verify SHA256 of the newly downloaded file
+            // Stream the body straight to a temp file while hashing it in-flight, so we
+            // never hold the whole geo-IP list in memory and never leave a half-written
+            // LOCAL_FILE_PATH behind if the transfer or the hash check fails.
+            let temp_path = format!("{}.tmp", LOCAL_FILE_PATH);
+            let calculated_hash = match stream_to_file(response, &temp_path).await {
+                Ok(hash) => hash,
+                Err(e) => {
+                    let _ = fs::remove_file(&temp_path);
+                    return Err(e);
+                }
+            };
+
             println!("Verifying integrity with SHA256 from: {}", SHA256_URL);
-            let sha256_response = client.get(SHA256_URL).send().await?;
+            let sha256_response = fetch::send_with_retry(|| client.get(SHA256_URL)).await?;
             let sha256_content = sha256_response.text().await?;
             let expected_hash = sha256_content.split_whitespace().next().unwrap_or("");
 
-            let mut hasher = Sha256::new();
-            hasher.update(&content);
-            let calculated_hash = format!("{:x}", hasher.finalize());
-
             if calculated_hash != expected_hash {
                 eprintln!("SHA256 mismatch! Downloaded file is corrupt.");
                 eprintln!("Expected:   {}", expected_hash);
                 eprintln!("Calculated: {}", calculated_hash);
-                std::process::exit(1);
+                fs::remove_file(&temp_path)?;
+                return Err(Box::new(FetchFileError::HashMismatch {
+                    expected: expected_hash.to_string(),
+                    calculated: calculated_hash,
+                }));
             }
             println!("SHA256 verification successful!");
 
-            // Save the new content to the local file
-            fs::write(LOCAL_FILE_PATH, &content)?;
+            // Atomically swap the verified temp file into place.
+            fs::rename(&temp_path, LOCAL_FILE_PATH)?;
             println!("Local file updated.");
-            content.to_vec()
+            fs::read(LOCAL_FILE_PATH)?
         }
         StatusCode::NOT_MODIFIED => {
             println!("Local file is already up-to-date. Processing local file.");
             fs::read(LOCAL_FILE_PATH)?
         }
-        _ => {
-            eprintln!("Failed to fetch file: {}", response.status());
-            std::process::exit(1);
+        status => {
+            eprintln!("Failed to fetch file: {}", status);
+            return Err(Box::new(FetchFileError::UnexpectedStatus(status)));
         }
     };
 
     // Process the content (either from download or local file)
-    process_and_grep(&content, &country_codes)?;
+    let mut all_blocks = process_and_grep(&content, &country_codes)?;
 
     // Process ASN data if any ASN numbers are provided
     if !args.asn_numbers.is_empty() {
-        process_asn_data(&client, &args.asn_numbers).await?;
+        let fetch_v6 = args.ipv6 || args.dual;
+        let fetch_v4 = !args.ipv6;
+        all_blocks.extend(
+            process_asn_data(client, &args.asn_numbers, fetch_v4, fetch_v6).await?,
+        );
+    }
+
+    if args.aggregate {
+        let before = all_blocks.len();
+        let cidrs: Vec<String> = all_blocks.iter().map(|b| b.cidr.clone()).collect();
+        let result = aggregate::aggregate(&cidrs);
+        // Aggregation can merge blocks that started out under different
+        // labels, so the per-block label is no longer meaningful afterwards.
+        all_blocks = result
+            .cidrs
+            .into_iter()
+            .map(|cidr| output::LabeledBlock {
+                cidr,
+                label: "OK".to_string(),
+            })
+            .collect();
+        println!(
+            "\nAggregated {} blocks down to {} ({} eliminated).",
+            before,
+            all_blocks.len(),
+            result.eliminated
+        );
     }
 
-    Ok(())
+    let output_path = args
+        .output
+        .clone()
+        .unwrap_or_else(|| args.format.default_filename().to_string());
+    let total_blocks = all_blocks.len();
+    let rendered = output::render(args.format, &all_blocks, &args.set_name).into_bytes();
+    fs::write(&output_path, &rendered)?;
+
+    Ok(GenerateOutput {
+        rendered,
+        output_path,
+        total_blocks,
+    })
 }
 
-fn process_and_grep(content: &[u8], country_codes: &[String]) -> io::Result<()> {
+fn process_and_grep(
+    content: &[u8],
+    country_codes: &[String],
+) -> io::Result<Vec<output::LabeledBlock>> {
     let reader = BufReader::new(content);
 
     println!(
@@ -109,7 +290,7 @@ fn process_and_grep(content: &[u8], country_codes: &[String]) -> io::Result<()>
     );
 
     let mut country_counts = std::collections::HashMap::new();
-    let mut filtered_lines = Vec::new();
+    let mut filtered_blocks = Vec::new();
 
     for line in reader.lines() {
         let line = line?;
@@ -120,18 +301,15 @@ fn process_and_grep(content: &[u8], country_codes: &[String]) -> io::Result<()>
             let country_code = columns[1];
 
             if country_codes.iter().any(|cc| cc == country_code) {
-                // Only store the CIDR block, not the country code
-                filtered_lines.push(cidr_block.to_string());
+                filtered_blocks.push(output::LabeledBlock {
+                    cidr: cidr_block.to_string(),
+                    label: country_code.to_string(),
+                });
                 *country_counts.entry(country_code.to_string()).or_insert(0) += 1;
             }
         }
     }
 
-    // Write filtered results to LOCAL_FILE_CIDR (CIDR blocks only)
-    let output_content = filtered_lines.join("\n");
-    fs::write(LOCAL_FILE_CIDR, &output_content)?;
-
-    println!("Filtered CIDR blocks written to: {}", LOCAL_FILE_CIDR);
     println!("\nSummary:");
 
     let mut total = 0;
@@ -143,76 +321,133 @@ fn process_and_grep(content: &[u8], country_codes: &[String]) -> io::Result<()>
 
     println!("Total matching blocks: {}", total);
 
-    Ok(())
+    Ok(filtered_blocks)
+}
+
+fn is_well_formed_ipv6_cidr(line: &str) -> bool {
+    match line.split_once('/') {
+        Some((addr, len)) => {
+            addr.parse::<std::net::Ipv6Addr>().is_ok()
+                && len.parse::<u8>().map(|l| l <= 128).unwrap_or(false)
+        }
+        None => false,
+    }
 }
 
 async fn process_asn_data(
     client: &reqwest::Client,
     asn_numbers: &[String],
-) -> Result<(), Box<dyn std::error::Error>> {
+    fetch_v4: bool,
+    fetch_v6: bool,
+) -> Result<Vec<output::LabeledBlock>, Box<dyn std::error::Error>> {
     println!("\nProcessing ASN data for: {:?}...", asn_numbers);
 
     let mut all_asn_blocks = Vec::new();
-    let mut asn_counts = std::collections::HashMap::new();
+    let mut asn_v4_counts = std::collections::HashMap::new();
+    let mut asn_v6_counts = std::collections::HashMap::new();
 
     for asn in asn_numbers {
-        let url = format!("{}/{}/ipv4-aggregated.txt", ASN_BASE_URL, asn);
-        println!("Fetching ASN data from: {}", url);
-
-        match client.get(&url).send().await {
-            Ok(response) => {
-                if response.status().is_success() {
-                    let content = response.text().await?;
-                    let lines: Vec<&str> = content.lines().collect();
-                    let count = lines.len();
-
-                    for line in lines {
-                        let line = line.trim();
-                        if !line.is_empty() {
-                            // Only store the CIDR block, not the ASN suffix
-                            all_asn_blocks.push(line.to_string());
-                        }
+        let label = format!("AS{}", asn);
+
+        if fetch_v4 {
+            let url = format!("{}/{}/ipv4-aggregated.txt", ASN_BASE_URL, asn);
+            println!("Fetching ASN data from: {}", url);
+
+            // A persistent (post-retry) failure here aborts the whole run rather
+            // than silently writing a block list that is missing this ASN's data.
+            let response = fetch::send_with_retry(|| client.get(&url)).await?;
+
+            if response.status().is_success() {
+                let content = response.text().await?;
+                let lines: Vec<&str> = content.lines().collect();
+                let count = lines.len();
+
+                for line in lines {
+                    let line = line.trim();
+                    if !line.is_empty() {
+                        all_asn_blocks.push(output::LabeledBlock {
+                            cidr: line.to_string(),
+                            label: label.clone(),
+                        });
                     }
-
-                    asn_counts.insert(asn.clone(), count);
-                    println!("AS{} CIDR blocks fetched: {}", asn, count);
-                } else {
-                    eprintln!(
-                        "Warning: Failed to fetch AS{}: HTTP {}",
-                        asn,
-                        response.status()
-                    );
                 }
-            }
-            Err(e) => {
-                eprintln!("Warning: Error fetching AS{}: {}", asn, e);
+
+                asn_v4_counts.insert(asn.clone(), count);
+                println!("AS{} IPv4 CIDR blocks fetched: {}", asn, count);
+            } else {
+                eprintln!(
+                    "Warning: Failed to fetch AS{} (IPv4): HTTP {}",
+                    asn,
+                    response.status()
+                );
             }
         }
-    }
 
-    // Append ASN blocks to the existing okcidr.txt file
-    if !all_asn_blocks.is_empty() {
-        let mut existing_content =
-            fs::read_to_string(LOCAL_FILE_CIDR).unwrap_or_else(|_| String::new());
+        if fetch_v6 {
+            let url = format!("{}/{}/ipv6-aggregated.txt", ASN_BASE_URL, asn);
+            println!("Fetching ASN data from: {}", url);
 
-        if !existing_content.is_empty() && !existing_content.ends_with('\n') {
-            existing_content.push('\n');
-        }
+            let response = fetch::send_with_retry(|| client.get(&url)).await?;
+
+            if response.status().is_success() {
+                let content = response.text().await?;
+                let mut count = 0;
 
-        existing_content.push_str(&all_asn_blocks.join("\n"));
-        fs::write(LOCAL_FILE_CIDR, existing_content)?;
+                for line in content.lines() {
+                    let line = line.trim();
+                    if line.is_empty() {
+                        continue;
+                    }
+                    if is_well_formed_ipv6_cidr(line) {
+                        all_asn_blocks.push(output::LabeledBlock {
+                            cidr: line.to_string(),
+                            label: label.clone(),
+                        });
+                        count += 1;
+                    } else {
+                        eprintln!(
+                            "Warning: skipping malformed IPv6 prefix for AS{}: {}",
+                            asn, line
+                        );
+                    }
+                }
+
+                asn_v6_counts.insert(asn.clone(), count);
+                println!("AS{} IPv6 CIDR blocks fetched: {}", asn, count);
+            } else {
+                eprintln!(
+                    "Warning: Failed to fetch AS{} (IPv6): HTTP {}",
+                    asn,
+                    response.status()
+                );
+            }
+        }
+    }
 
-        println!("\nASN CIDR blocks appended to: {}", LOCAL_FILE_CIDR);
+    if !all_asn_blocks.is_empty() {
         println!("\nASN Summary:");
 
-        let mut total = 0;
+        let mut total_v4 = 0;
+        let mut total_v6 = 0;
         for asn in asn_numbers {
-            let count = asn_counts.get(asn).unwrap_or(&0);
-            println!("AS{} CIDR blocks: {}", asn, count);
-            total += count;
+            if fetch_v4 {
+                let count = asn_v4_counts.get(asn).unwrap_or(&0);
+                println!("AS{} IPv4 CIDR blocks: {}", asn, count);
+                total_v4 += count;
+            }
+            if fetch_v6 {
+                let count = asn_v6_counts.get(asn).unwrap_or(&0);
+                println!("AS{} IPv6 CIDR blocks: {}", asn, count);
+                total_v6 += count;
+            }
+        }
+        if fetch_v4 {
+            println!("Total IPv4 ASN blocks: {}", total_v4);
+        }
+        if fetch_v6 {
+            println!("Total IPv6 ASN blocks: {}", total_v6);
         }
-        println!("Total ASN blocks: {}", total);
     }
 
-    Ok(())
+    Ok(all_asn_blocks)
 }
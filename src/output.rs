@@ -0,0 +1,155 @@
+//! Serialization of the filtered CIDR list into the different formats
+//! downstream consumers expect (HAProxy ACL, HAProxy map, nftables, ipset).
+
+use std::net::Ipv6Addr;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Bare newline-separated CIDR list (the original behavior)
+    Cidr,
+    /// `CIDR label` pairs for HAProxy's `map()` converter
+    HaproxyMap,
+    /// An nftables `define <name>_v4/_v6 = { ... }` element list
+    Nftables,
+    /// `add <set> <cidr>` ipset restore-file lines
+    Ipset,
+}
+
+impl Format {
+    pub fn default_filename(self) -> &'static str {
+        match self {
+            Format::Cidr => "okcidr.txt",
+            Format::HaproxyMap => "ok.map",
+            Format::Nftables => "ok_set.nft",
+            Format::Ipset => "ok.ipset",
+        }
+    }
+
+    /// MIME type to advertise when `serve` publishes this format over HTTP.
+    /// None of these formats has a registered IANA type, so they're all
+    /// served as plain text rather than the `application/octet-stream`
+    /// default.
+    pub fn content_type(self) -> &'static str {
+        "text/plain; charset=utf-8"
+    }
+}
+
+/// A CIDR block together with the label it was matched under (a country
+/// code, an "ASN" tag, or a synthetic placeholder once blocks have been
+/// aggregated across labels).
+pub struct LabeledBlock {
+    pub cidr: String,
+    pub label: String,
+}
+
+fn is_v6(cidr: &str) -> bool {
+    cidr.split('/')
+        .next()
+        .and_then(|addr| addr.parse::<Ipv6Addr>().ok())
+        .is_some()
+}
+
+pub fn render(format: Format, blocks: &[LabeledBlock], set_name: &str) -> String {
+    match format {
+        Format::Cidr => blocks
+            .iter()
+            .map(|b| b.cidr.as_str())
+            .collect::<Vec<_>>()
+            .join("\n"),
+        Format::HaproxyMap => blocks
+            .iter()
+            .map(|b| format!("{} {}", b.cidr, b.label))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        Format::Nftables => render_nftables(blocks, set_name),
+        Format::Ipset => blocks
+            .iter()
+            .map(|b| format!("add {} {}", set_name, b.cidr))
+            .collect::<Vec<_>>()
+            .join("\n"),
+    }
+}
+
+fn render_nftables(blocks: &[LabeledBlock], set_name: &str) -> String {
+    let mut v4 = Vec::new();
+    let mut v6 = Vec::new();
+
+    for block in blocks {
+        if is_v6(&block.cidr) {
+            v6.push(block.cidr.as_str());
+        } else {
+            v4.push(block.cidr.as_str());
+        }
+    }
+
+    format!(
+        "define {}_v4 = {{ {} }}\ndefine {}_v6 = {{ {} }}",
+        set_name,
+        v4.join(", "),
+        set_name,
+        v6.join(", ")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(cidr: &str, label: &str) -> LabeledBlock {
+        LabeledBlock {
+            cidr: cidr.to_string(),
+            label: label.to_string(),
+        }
+    }
+
+    #[test]
+    fn renders_bare_cidr_list() {
+        let blocks = vec![block("10.0.0.0/24", "US"), block("192.168.0.0/16", "CA")];
+        assert_eq!(
+            render(Format::Cidr, &blocks, "ok_set"),
+            "10.0.0.0/24\n192.168.0.0/16"
+        );
+    }
+
+    #[test]
+    fn renders_haproxy_map_pairs_preserving_labels() {
+        let blocks = vec![block("10.0.0.0/24", "US"), block("2001:db8::/32", "AS64500")];
+        assert_eq!(
+            render(Format::HaproxyMap, &blocks, "ok_set"),
+            "10.0.0.0/24 US\n2001:db8::/32 AS64500"
+        );
+    }
+
+    #[test]
+    fn renders_ipset_restore_lines() {
+        let blocks = vec![block("10.0.0.0/24", "US")];
+        assert_eq!(render(Format::Ipset, &blocks, "ok_set"), "add ok_set 10.0.0.0/24");
+    }
+
+    #[test]
+    fn nftables_splits_v4_and_v6_into_separate_sets() {
+        let blocks = vec![
+            block("10.0.0.0/24", "US"),
+            block("2001:db8::/32", "US"),
+            block("192.168.0.0/16", "CA"),
+        ];
+        assert_eq!(
+            render(Format::Nftables, &blocks, "ok_set"),
+            "define ok_set_v4 = { 10.0.0.0/24, 192.168.0.0/16 }\ndefine ok_set_v6 = { 2001:db8::/32 }"
+        );
+    }
+
+    #[test]
+    fn nftables_handles_empty_block_list() {
+        assert_eq!(
+            render(Format::Nftables, &[], "ok_set"),
+            "define ok_set_v4 = {  }\ndefine ok_set_v6 = {  }"
+        );
+    }
+
+    #[test]
+    fn is_v6_distinguishes_families() {
+        assert!(is_v6("2001:db8::/32"));
+        assert!(!is_v6("10.0.0.0/24"));
+    }
+}
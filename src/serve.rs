@@ -0,0 +1,171 @@
+//! Long-running daemon mode: periodically refresh the filtered block list
+//! and publish it over HTTP with conditional GET support, so a fleet of
+//! HAProxy nodes can pull an always-current allow-list with cheap
+//! revalidation instead of each one scraping the upstream sources itself.
+
+use crate::{run_generate, tls, GenerateArgs};
+use axum::extract::State;
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::sync::RwLock;
+use tokio::time::{interval, Duration};
+
+#[derive(clap::Parser, Debug)]
+pub(crate) struct ServeArgs {
+    #[command(flatten)]
+    generate: GenerateArgs,
+
+    /// Seconds between automatic refreshes of the upstream data
+    #[arg(long = "interval", default_value_t = 3600)]
+    interval_secs: u64,
+
+    /// Address to bind the HTTP server to
+    #[arg(long = "bind", default_value = "0.0.0.0:8080")]
+    bind: String,
+}
+
+struct Published {
+    body: Vec<u8>,
+    content_type: &'static str,
+    etag: String,
+    last_modified: SystemTime,
+    total_blocks: usize,
+    refreshed_at: SystemTime,
+}
+
+type SharedState = Arc<RwLock<Option<Published>>>;
+
+pub(crate) async fn run(args: ServeArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let client = tls::build_client(args.generate.pin_sha256.as_deref())?;
+    let state: SharedState = Arc::new(RwLock::new(None));
+
+    refresh(&client, &args.generate, &state).await?;
+
+    let refresh_client = client.clone();
+    let generate_args = args.generate.clone();
+    let refresh_state = state.clone();
+    let refresh_interval = Duration::from_secs(args.interval_secs.max(1));
+
+    tokio::spawn(async move {
+        let mut ticker = interval(refresh_interval);
+        ticker.tick().await; // first tick fires immediately; we already refreshed above
+        loop {
+            ticker.tick().await;
+            if let Err(e) = refresh(&refresh_client, &generate_args, &refresh_state).await {
+                eprintln!("Warning: periodic refresh failed: {}", e);
+            }
+        }
+    });
+
+    // The route mirrors the one-shot command's output filename, so the URL
+    // makes clear which format (--format) is being served.
+    let route_path = format!("/{}", args.generate.format.default_filename());
+    let app = Router::new()
+        .route(&route_path, get(serve_blocklist))
+        .route("/health", get(health))
+        .with_state(state);
+
+    println!("Serving block list on http://{}{}", args.bind, route_path);
+    let listener = tokio::net::TcpListener::bind(&args.bind).await?;
+    axum::serve(listener, app).await?;
+
+    Ok(())
+}
+
+async fn refresh(
+    client: &reqwest::Client,
+    args: &GenerateArgs,
+    state: &SharedState,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let output = run_generate(client, args).await?;
+
+    let mut hasher = Sha256::new();
+    hasher.update(&output.rendered);
+    let etag = format!("\"{:x}\"", hasher.finalize());
+
+    println!(
+        "\nRefreshed block list: {} blocks written to {}",
+        output.total_blocks, output.output_path
+    );
+
+    *state.write().await = Some(Published {
+        body: output.rendered,
+        content_type: args.format.content_type(),
+        etag,
+        last_modified: SystemTime::now(),
+        total_blocks: output.total_blocks,
+        refreshed_at: SystemTime::now(),
+    });
+
+    Ok(())
+}
+
+fn not_modified(published: &Published, headers: &HeaderMap) -> bool {
+    let etag_matches = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v == published.etag)
+        .unwrap_or(false);
+
+    let not_modified_since = headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| httpdate::parse_http_date(v).ok())
+        .map(|since| published.last_modified <= since)
+        .unwrap_or(false);
+
+    etag_matches || not_modified_since
+}
+
+async fn serve_blocklist(State(state): State<SharedState>, headers: HeaderMap) -> Response {
+    let guard = state.read().await;
+    let Some(published) = guard.as_ref() else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "block list not yet generated").into_response();
+    };
+
+    if not_modified(published, &headers) {
+        return StatusCode::NOT_MODIFIED.into_response();
+    }
+
+    let mut response_headers = HeaderMap::new();
+    response_headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(published.content_type),
+    );
+    response_headers.insert(header::ETAG, HeaderValue::from_str(&published.etag).unwrap());
+    response_headers.insert(
+        header::LAST_MODIFIED,
+        HeaderValue::from_str(&httpdate::fmt_http_date(published.last_modified)).unwrap(),
+    );
+    response_headers.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static("public, max-age=60, must-revalidate"),
+    );
+
+    (StatusCode::OK, response_headers, published.body.clone()).into_response()
+}
+
+async fn health(State(state): State<SharedState>) -> Response {
+    let guard = state.read().await;
+    match guard.as_ref() {
+        Some(published) => {
+            let body = format!(
+                "{{\"status\":\"ok\",\"last_refresh\":\"{}\",\"block_count\":{}}}",
+                httpdate::fmt_http_date(published.refreshed_at),
+                published.total_blocks
+            );
+            (
+                StatusCode::OK,
+                [(header::CONTENT_TYPE, "application/json")],
+                body,
+            )
+                .into_response()
+        }
+        None => (StatusCode::SERVICE_UNAVAILABLE, "not yet generated").into_response(),
+    }
+}
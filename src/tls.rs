@@ -0,0 +1,107 @@
+//! Client construction: a rustls backend with native roots, plus an
+//! optional SPKI pin so a hijacked TLS endpoint can't silently widen the
+//! allow-list this tool produces.
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+/// Wraps the default webpki verifier and additionally requires the leaf
+/// certificate's SubjectPublicKeyInfo to hash to a pinned SHA256 value.
+#[derive(Debug)]
+struct PinnedSpkiVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    expected_spki_sha256: String,
+}
+
+impl ServerCertVerifier for PinnedSpkiVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.inner
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+
+        let (_, cert) = x509_parser::parse_x509_certificate(end_entity.as_ref())
+            .map_err(|e| rustls::Error::General(format!("failed to parse certificate: {}", e)))?;
+        let spki_der = cert.tbs_certificate.subject_pki.raw;
+
+        let mut hasher = Sha256::new();
+        hasher.update(spki_der);
+        let actual = format!("{:x}", hasher.finalize());
+
+        if actual.eq_ignore_ascii_case(&self.expected_spki_sha256) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(format!(
+                "SPKI pin mismatch for {:?}: expected {}, got {}",
+                server_name, self.expected_spki_sha256, actual
+            )))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+fn native_root_store() -> RootCertStore {
+    let mut store = RootCertStore::empty();
+    for cert in rustls_native_certs::load_native_certs().unwrap_or_default() {
+        let _ = store.add(cert);
+    }
+    store
+}
+
+/// Build the shared HTTP client used for every upstream fetch. When
+/// `pin_sha256` is set, connections are rejected unless the server's leaf
+/// certificate SPKI hashes to that value.
+pub(crate) fn build_client(
+    pin_sha256: Option<&str>,
+) -> Result<reqwest::Client, Box<dyn std::error::Error>> {
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    let root_store = native_root_store();
+    let mut config = ClientConfig::builder()
+        .with_root_certificates(root_store.clone())
+        .with_no_client_auth();
+
+    if let Some(pin) = pin_sha256 {
+        let inner = WebPkiServerVerifier::builder(Arc::new(root_store)).build()?;
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(PinnedSpkiVerifier {
+                inner,
+                expected_spki_sha256: pin.to_lowercase(),
+            }));
+    }
+
+    Ok(reqwest::Client::builder()
+        .use_preconfigured_tls(config)
+        .build()?)
+}